@@ -2,24 +2,80 @@ pub use once_cell;
 use once_cell::sync::Lazy;
 pub use parking_lot;
 use parking_lot::RwLock;
+use std::cell::RefCell;
 use std::ops::Deref;
+use std::thread::LocalKey;
 
 // Wrapper structs for clear trait resolution
-pub struct GlobalVar<T>(pub Lazy<RwLock<T>>);
+//
+// The second field is a pointer to the per-global thread-local override
+// stack emitted by the `globals!` macro; it backs `GlobalVar::using` and
+// lets `get`/`get_with`/`update` check for an active override before
+// touching the lock (or cell, under `single_threaded`).
+#[cfg(not(feature = "single_threaded"))]
+pub struct GlobalVar<T: 'static>(pub Lazy<RwLock<T>>, pub &'static LocalKey<RefCell<Vec<T>>>);
+
+/// Single-threaded counterpart of the `RwLock`-backed `GlobalVar`, used when
+/// the `single_threaded` feature is enabled: it swaps the lock out for a
+/// plain `RefCell`, so a program that only ever touches its globals from one
+/// thread doesn't pay for atomics or lock acquisition it never needed.
+///
+/// # Safety
+/// `GlobalVar<T>` is declared `Sync` here even though its backing `RefCell<T>`
+/// is not, so that it can still live in a `static`; it is on the caller to
+/// uphold the single-threaded contract `single_threaded` promises — this type
+/// must never actually be accessed from more than one OS thread. The `T:
+/// Send` bound only rules out payloads (like `Rc<_>`, whose refcount isn't
+/// atomic) that would be unsound to so much as move onto another thread,
+/// which an ordinary `std::thread::spawn` closure capturing this global can
+/// do even under an honored single-threaded contract.
+#[cfg(feature = "single_threaded")]
+pub struct GlobalVar<T: 'static>(pub Lazy<RefCell<T>>, pub &'static LocalKey<RefCell<Vec<T>>>);
+#[cfg(feature = "single_threaded")]
+unsafe impl<T: Send> Sync for GlobalVar<T> {}
+
 pub struct GlobalConst<T>(pub Lazy<T>);
 
+// Backs `globals_group!`: several fields behind a single lock instead of one
+// lock per field, so a caller can mutate more than one of them under a
+// single acquisition and get coherency between them for free.
+pub struct GlobalGroup<T: 'static>(pub Lazy<RwLock<T>>);
+
 #[macro_export]
 macro_rules! globals {
     {$(
         $name:ident : $ty:ty $(= $expr:expr)?
     ),* $(,)?} => {
         $(
+            // `mod` and `static` live in separate namespaces, so this module
+            // can share the global's name while holding its override stack.
+            #[allow(non_snake_case)]
+            mod $name {
+                ::std::thread_local! {
+                    pub(super) static __OVERRIDES: ::std::cell::RefCell<::std::vec::Vec<$ty>> =
+                        ::std::cell::RefCell::new(::std::vec::Vec::new());
+                }
+            }
+
+            #[cfg(not(feature = "single_threaded"))]
             #[allow(non_upper_case_globals)]
             static $name: $crate::GlobalVar<$ty> = $crate::GlobalVar(
                 $crate::once_cell::sync::Lazy::new(|| $crate::parking_lot::RwLock::new(
                         globals!(@init_expr $ty, $($expr)?)
                     )
-                ));
+                ),
+                &$name::__OVERRIDES,
+            );
+
+            #[cfg(feature = "single_threaded")]
+            #[allow(non_upper_case_globals)]
+            static $name: $crate::GlobalVar<$ty> = $crate::GlobalVar(
+                $crate::once_cell::sync::Lazy::new(|| ::std::cell::RefCell::new(
+                        globals!(@init_expr $ty, $($expr)?)
+                    )
+                ),
+                &$name::__OVERRIDES,
+            );
         )*
     };
     (@init_expr $ty:ty, $expr:expr) => { $expr };
@@ -43,11 +99,89 @@ macro_rules! const_globals {
     (@init_expr $ty:ty,) => { <$ty>::default() };
 }
 
+#[macro_export]
+macro_rules! globals_group {
+    ($group:ident { $($field:ident : $ty:ty $(= $expr:expr)?),* $(,)? }) => {
+        #[allow(non_camel_case_types)]
+        pub struct $group {
+            $(pub $field: $ty,)*
+        }
+
+        impl ::std::default::Default for $group {
+            fn default() -> Self {
+                Self {
+                    $($field: globals_group!(@init_expr $ty, $($expr)?),)*
+                }
+            }
+        }
+
+        #[allow(non_upper_case_globals)]
+        static $group: $crate::GlobalGroup<$group> = $crate::GlobalGroup(
+            $crate::once_cell::sync::Lazy::new(|| $crate::parking_lot::RwLock::new(
+                <$group as ::std::default::Default>::default()
+            ))
+        );
+    };
+    (@init_expr $ty:ty, $expr:expr) => { $expr };
+    (@init_expr $ty:ty,) => { <$ty>::default() };
+}
+
+impl<T> GlobalGroup<T> {
+    /// Mutates every field of the group under a single lock acquisition,
+    /// guaranteeing they're updated atomically with respect to each other.
+    pub fn with<F, R>(&self, f: F) -> R where F: FnOnce(&mut T) -> R {
+        f(&mut *self.0.write())
+    }
+
+    /// Read-only access to the group under one acquisition.
+    pub fn read_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R {
+        f(&self.0.read())
+    }
+
+    /// Alias for [`GlobalGroup::with`], mirroring `read_with`.
+    pub fn write_with<F, R>(&self, f: F) -> R where F: FnOnce(&mut T) -> R {
+        self.with(f)
+    }
+}
+
 pub trait GlobalVarExt<T> {
     fn get(&self) -> T where T: Clone;
     fn get_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R;
     fn set(&self, value: T);
     fn update<F>(&self, f: F) where F: FnOnce(&mut T);
+
+    /// Returns the active `using` override if there is one; otherwise a
+    /// non-blocking read of the real value, or `None` if the lock is
+    /// currently held for writing. For latency-sensitive callers that would
+    /// rather see a stale/absent value than block on a contended global.
+    #[cfg(not(feature = "single_threaded"))]
+    fn try_get(&self) -> Option<T> where T: Clone;
+
+    /// Non-blocking mutation; returns `false` without calling `f` if the
+    /// lock couldn't be acquired for writing.
+    #[cfg(not(feature = "single_threaded"))]
+    fn try_update<F>(&self, f: F) -> bool where F: FnOnce(&mut T);
+
+    /// Like [`GlobalVarExt::get`], but gives up and returns `None` after
+    /// `timeout` instead of blocking indefinitely.
+    #[cfg(not(feature = "single_threaded"))]
+    fn get_timeout(&self, timeout: std::time::Duration) -> Option<T> where T: Clone;
+
+    /// Like [`GlobalVarExt::update`], but gives up and returns `false`
+    /// without calling `f` after `timeout` instead of blocking indefinitely.
+    #[cfg(not(feature = "single_threaded"))]
+    fn update_timeout<F>(&self, timeout: std::time::Duration, f: F) -> bool where F: FnOnce(&mut T);
+
+    /// Takes an upgradable read lock and hands the guard to `f`, which can
+    /// read through it or call `RwLockUpgradableReadGuard::upgrade` to get a
+    /// write guard without ever dropping the lock in between — so a caller
+    /// that usually reads a global and only occasionally needs to mutate it
+    /// can't have another writer sneak in between the read and the write.
+    #[cfg(not(feature = "single_threaded"))]
+    fn with_upgradable<F, R>(&self, f: F) -> R
+    where
+        T: Clone,
+        F: for<'a> FnOnce(parking_lot::RwLockUpgradableReadGuard<'a, T>) -> R;
 }
 
 pub trait GlobalConstExt<T> {
@@ -55,21 +189,167 @@ pub trait GlobalConstExt<T> {
     fn get_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R;
 }
 
-impl<T> GlobalVarExt<T> for GlobalVar<T> {
+#[cfg(not(feature = "single_threaded"))]
+impl<T: 'static> GlobalVarExt<T> for GlobalVar<T> {
     fn get(&self) -> T where T: Clone {
-        self.0.read().clone()
+        match self.1.with(|stack| stack.borrow().last().cloned()) {
+            Some(value) => value,
+            None => self.0.read().clone(),
+        }
     }
 
     fn get_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R {
-        f(&self.0.read())
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            f(&self.0.read())
+        } else {
+            self.1.with(|stack| f(stack.borrow().last().unwrap()))
+        }
+    }
+
+    fn set(&self, value: T) {
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            *self.0.write() = value;
+        } else {
+            self.1.with(|stack| *stack.borrow_mut().last_mut().unwrap() = value);
+        }
+    }
+
+    fn update<F>(&self, f: F) where F: FnOnce(&mut T) {
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            f(&mut *self.0.write());
+        } else {
+            self.1.with(|stack| f(stack.borrow_mut().last_mut().unwrap()));
+        }
+    }
+
+    fn try_get(&self) -> Option<T> where T: Clone {
+        match self.1.with(|stack| stack.borrow().last().cloned()) {
+            Some(value) => Some(value),
+            None => self.0.try_read().map(|guard| guard.clone()),
+        }
+    }
+
+    fn try_update<F>(&self, f: F) -> bool where F: FnOnce(&mut T) {
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            match self.0.try_write() {
+                Some(mut guard) => {
+                    f(&mut guard);
+                    true
+                }
+                None => false,
+            }
+        } else {
+            self.1.with(|stack| f(stack.borrow_mut().last_mut().unwrap()));
+            true
+        }
+    }
+
+    fn get_timeout(&self, timeout: std::time::Duration) -> Option<T> where T: Clone {
+        match self.1.with(|stack| stack.borrow().last().cloned()) {
+            Some(value) => Some(value),
+            None => self.0.try_read_for(timeout).map(|guard| guard.clone()),
+        }
+    }
+
+    fn update_timeout<F>(&self, timeout: std::time::Duration, f: F) -> bool where F: FnOnce(&mut T) {
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            match self.0.try_write_for(timeout) {
+                Some(mut guard) => {
+                    f(&mut guard);
+                    true
+                }
+                None => false,
+            }
+        } else {
+            self.1.with(|stack| f(stack.borrow_mut().last_mut().unwrap()));
+            true
+        }
+    }
+
+    fn with_upgradable<F, R>(&self, f: F) -> R
+    where
+        T: Clone,
+        F: for<'a> FnOnce(parking_lot::RwLockUpgradableReadGuard<'a, T>) -> R,
+    {
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            f(self.0.upgradable_read())
+        } else {
+            // An override is active: run `f` against a private lock seeded
+            // with the overridden value so it still gets a real
+            // `RwLockUpgradableReadGuard`, then write any upgrade-and-mutate
+            // back onto the top of the override stack instead of leaking it
+            // into the real backing store.
+            let temp = RwLock::new(self.1.with(|stack| stack.borrow().last().cloned().unwrap()));
+            let result = f(temp.upgradable_read());
+            self.1.with(|stack| {
+                *stack.borrow_mut().last_mut().unwrap() = temp.into_inner();
+            });
+            result
+        }
+    }
+}
+
+#[cfg(feature = "single_threaded")]
+impl<T: 'static> GlobalVarExt<T> for GlobalVar<T> {
+    fn get(&self) -> T where T: Clone {
+        match self.1.with(|stack| stack.borrow().last().cloned()) {
+            Some(value) => value,
+            None => self.0.borrow().clone(),
+        }
+    }
+
+    fn get_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R {
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            f(&self.0.borrow())
+        } else {
+            self.1.with(|stack| f(stack.borrow().last().unwrap()))
+        }
     }
 
     fn set(&self, value: T) {
-        *self.0.write() = value;
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            *self.0.borrow_mut() = value;
+        } else {
+            self.1.with(|stack| *stack.borrow_mut().last_mut().unwrap() = value);
+        }
     }
 
     fn update<F>(&self, f: F) where F: FnOnce(&mut T) {
-        f(&mut *self.0.write());
+        if self.1.with(|stack| stack.borrow().is_empty()) {
+            f(&mut *self.0.borrow_mut());
+        } else {
+            self.1.with(|stack| f(stack.borrow_mut().last_mut().unwrap()));
+        }
+    }
+}
+
+impl<T: 'static> GlobalVar<T> {
+    /// Shadows this global with `temp` for the dynamic extent of `f`, on the
+    /// calling thread only. Every `get`/`get_with`/`update` call made while
+    /// `f` runs (directly or through code it calls) sees `temp` instead of
+    /// the shared value; the original is untouched and other threads are
+    /// unaffected. The override is popped even if `f` panics.
+    ///
+    /// `get_with`/`update` hold the override stack's `RefCell` borrowed for
+    /// the duration of the closure they're given, so calling a mutating
+    /// accessor on *this same global* again from inside that closure —
+    /// directly, or via a nested `using` — panics with "already borrowed" instead of the
+    /// plain deadlock you'd get against the real lock outside a `using`
+    /// scope. Don't reenter a global from within its own accessor closure.
+    pub fn using<R>(&self, temp: T, f: impl FnOnce() -> R) -> R {
+        self.1.with(|stack| stack.borrow_mut().push(temp));
+
+        struct PopGuard<T: 'static>(&'static LocalKey<RefCell<Vec<T>>>);
+        impl<T> Drop for PopGuard<T> {
+            fn drop(&mut self) {
+                self.0.with(|stack| {
+                    stack.borrow_mut().pop();
+                });
+            }
+        }
+        let _guard = PopGuard(self.1);
+
+        f()
     }
 }
 
@@ -81,4 +361,253 @@ impl<T> GlobalConstExt<T> for GlobalConst<T> {
     fn get_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R {
         f(self.0.deref())
     }
+}
+
+// Async analogue of `GlobalVar`/`globals!`/`GlobalVarExt`, gated behind the
+// `tokio` feature. A `parking_lot::RwLock` guard held across an `.await`
+// blocks the executor thread instead of yielding it, so these globals are
+// backed by `tokio::sync::RwLock` instead, whose guards are safe to hold
+// across await points. Sync and async globals can be mixed freely in one
+// crate.
+#[cfg(feature = "tokio")]
+pub use tokio;
+
+#[cfg(feature = "tokio")]
+pub struct AsyncGlobalVar<T: 'static>(pub Lazy<tokio::sync::RwLock<T>>);
+
+#[cfg(feature = "tokio")]
+#[macro_export]
+macro_rules! async_globals {
+    {$(
+        $name:ident : $ty:ty $(= $expr:expr)?
+    ),* $(,)?} => {
+        $(
+            #[allow(non_upper_case_globals)]
+            static $name: $crate::AsyncGlobalVar<$ty> = $crate::AsyncGlobalVar(
+                $crate::once_cell::sync::Lazy::new(|| $crate::tokio::sync::RwLock::new(
+                        async_globals!(@init_expr $ty, $($expr)?)
+                    )
+                ));
+        )*
+    };
+    (@init_expr $ty:ty, $expr:expr) => { $expr };
+    (@init_expr $ty:ty,) => { <$ty>::default() };
+}
+
+// `async fn` in a public trait doesn't let callers name the returned future
+// or require it be `Send`, which normally matters for a trait meant to be
+// used generically or boxed. `AsyncGlobalVarExt` is only ever implemented
+// for `AsyncGlobalVar` and awaited directly against a concrete global, never
+// stored as a trait object, so the lint's concern doesn't apply here; allow
+// it rather than hand-rolling `-> impl Future + Send` for every method.
+#[cfg(feature = "tokio")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncGlobalVarExt<T> {
+    async fn get(&self) -> T where T: Clone;
+    async fn get_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R;
+    async fn set(&self, value: T);
+    async fn update<F>(&self, f: F) where F: FnOnce(&mut T);
+}
+
+#[cfg(feature = "tokio")]
+impl<T> AsyncGlobalVarExt<T> for AsyncGlobalVar<T> {
+    async fn get(&self) -> T where T: Clone {
+        self.0.read().await.clone()
+    }
+
+    async fn get_with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R {
+        f(&*self.0.read().await)
+    }
+
+    async fn set(&self, value: T) {
+        *self.0.write().await = value;
+    }
+
+    async fn update<F>(&self, f: F) where F: FnOnce(&mut T) {
+        f(&mut *self.0.write().await);
+    }
+}
+
+#[cfg(all(test, not(feature = "single_threaded")))]
+mod accessor_override_tests {
+    use super::*;
+    use std::time::Duration;
+
+    globals! {
+        ACC_TRY_GET: i32 = 0,
+        ACC_TRY_UPDATE: i32 = 0,
+        ACC_GET_TIMEOUT: i32 = 0,
+        ACC_UPDATE_TIMEOUT: i32 = 0,
+        ACC_UPGRADABLE: i32 = 0,
+    }
+
+    #[test]
+    fn try_get_sees_override() {
+        ACC_TRY_GET.set(1);
+        ACC_TRY_GET.using(7, || {
+            assert_eq!(ACC_TRY_GET.try_get(), Some(7));
+        });
+        assert_eq!(ACC_TRY_GET.get(), 1);
+    }
+
+    #[test]
+    fn try_update_mutates_override_not_the_real_global() {
+        ACC_TRY_UPDATE.set(1);
+        ACC_TRY_UPDATE.using(7, || {
+            assert!(ACC_TRY_UPDATE.try_update(|v| *v += 1));
+            assert_eq!(ACC_TRY_UPDATE.get(), 8);
+        });
+        assert_eq!(ACC_TRY_UPDATE.get(), 1);
+    }
+
+    #[test]
+    fn get_timeout_sees_override() {
+        ACC_GET_TIMEOUT.set(1);
+        ACC_GET_TIMEOUT.using(7, || {
+            assert_eq!(ACC_GET_TIMEOUT.get_timeout(Duration::from_millis(10)), Some(7));
+        });
+        assert_eq!(ACC_GET_TIMEOUT.get(), 1);
+    }
+
+    #[test]
+    fn update_timeout_mutates_override_not_the_real_global() {
+        ACC_UPDATE_TIMEOUT.set(1);
+        ACC_UPDATE_TIMEOUT.using(7, || {
+            assert!(ACC_UPDATE_TIMEOUT.update_timeout(Duration::from_millis(10), |v| *v += 1));
+            assert_eq!(ACC_UPDATE_TIMEOUT.get(), 8);
+        });
+        assert_eq!(ACC_UPDATE_TIMEOUT.get(), 1);
+    }
+
+    #[test]
+    fn with_upgradable_reads_and_writes_the_override() {
+        ACC_UPGRADABLE.set(1);
+        ACC_UPGRADABLE.using(7, || {
+            let seen = ACC_UPGRADABLE.with_upgradable(|guard| {
+                let mut write = parking_lot::RwLockUpgradableReadGuard::upgrade(guard);
+                *write += 1;
+                *write
+            });
+            assert_eq!(seen, 8);
+            assert_eq!(ACC_UPGRADABLE.get(), 8);
+        });
+        assert_eq!(ACC_UPGRADABLE.get(), 1);
+    }
+}
+
+#[cfg(test)]
+mod group_tests {
+    globals_group! {
+        GroupA {
+            a: u32 = 0,
+            b: u32 = 0,
+        }
+    }
+
+    globals_group! {
+        GroupB {
+            x: u32 = 0,
+        }
+    }
+
+    #[test]
+    fn with_mutates_multiple_fields_under_one_acquisition() {
+        GroupA.with(|g| {
+            g.a += 1;
+            g.b += 2;
+        });
+        GroupA.read_with(|g| {
+            assert_eq!(g.a, 1);
+            assert_eq!(g.b, 2);
+        });
+    }
+
+    #[test]
+    fn write_with_is_an_alias_for_with() {
+        GroupB.write_with(|g| g.x += 10);
+        assert_eq!(GroupB.read_with(|g| g.x), 10);
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod async_tests {
+    use super::*;
+
+    async_globals! {
+        ASYNC_COUNTER: i32 = 0,
+    }
+
+    #[tokio::test]
+    async fn get_set_update_roundtrip() {
+        ASYNC_COUNTER.set(5).await;
+        assert_eq!(ASYNC_COUNTER.get().await, 5);
+        ASYNC_COUNTER.update(|v| *v += 1).await;
+        assert_eq!(ASYNC_COUNTER.get().await, 6);
+    }
+}
+
+#[cfg(all(test, not(feature = "single_threaded")))]
+mod using_tests {
+    use super::*;
+
+    globals! {
+        USING_BASIC: i32 = 0,
+        USING_PANIC: i32 = 0,
+        USING_SET: i32 = 0,
+    }
+
+    #[test]
+    fn using_shadows_then_restores() {
+        USING_BASIC.set(1);
+        USING_BASIC.using(99, || {
+            assert_eq!(USING_BASIC.get(), 99);
+        });
+        assert_eq!(USING_BASIC.get(), 1);
+    }
+
+    #[test]
+    fn using_pops_on_panic() {
+        USING_PANIC.set(2);
+        let result = std::panic::catch_unwind(|| {
+            USING_PANIC.using(42, || {
+                panic!("boom");
+            });
+        });
+        assert!(result.is_err());
+        assert_eq!(USING_PANIC.get(), 2);
+    }
+
+    #[test]
+    fn set_inside_using_does_not_leak_to_the_real_global() {
+        USING_SET.set(10);
+        USING_SET.using(999, || {
+            USING_SET.set(42);
+            assert_eq!(USING_SET.get(), 42);
+        });
+        assert_eq!(USING_SET.get(), 10);
+    }
+}
+
+#[cfg(all(test, feature = "single_threaded"))]
+mod single_threaded_tests {
+    use super::*;
+
+    globals! {
+        ST_COUNTER: i32 = 0,
+    }
+
+    #[test]
+    fn get_set_update_roundtrip() {
+        ST_COUNTER.set(1);
+        assert_eq!(ST_COUNTER.get(), 1);
+        ST_COUNTER.update(|v| *v += 1);
+        assert_eq!(ST_COUNTER.get(), 2);
+    }
+
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn global_var_is_sync() {
+        assert_sync::<GlobalVar<i32>>();
+    }
 }
\ No newline at end of file